@@ -6,7 +6,7 @@ use bit_vec::BitVec;
 use sourceimage::{RGB, SeedImage};
 use ndarray::prelude::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::cell::RefCell;
 use std::{f64, usize};
 
@@ -60,39 +60,69 @@ impl UncertainCell {
                 let x = count as f64 / possible_state_count;
                 x * x.ln()
             })
-            .map(|x| x * x.ln())
             .sum();
 
         Some(-entropy)
 
     }
 
-    pub fn collapse<T>(&self, concrete_states: &[(T, usize)]) {
+    pub fn collapse<T>(&self, concrete_states: &[(T, usize)]) -> usize {
         /// Marks all but a single state of the BitVec as forbidden, randomly chosen
         /// from the states still permitted and weighted by their frequency in the original image.
         let mut possible_states = self.possible_states.borrow_mut();
         let chosen_state = utils::masked_weighted_choice(concrete_states, &*possible_states);
         possible_states.clear();
         possible_states.set(chosen_state, true);
+        chosen_state
     }
 }
 
 
+/// One decision point in the backtracking search: the cell that was collapsed,
+/// the state chosen for it, and an undo log of the possible colors and
+/// possible states of just the cells this decision's collapse and propagation
+/// actually touched, plus any cell forbidden while rewinding later decisions,
+/// recorded from just before each cell's first mutation.
+struct Decision {
+    position: (usize, usize),
+    chosen_state: usize,
+    undo_log: Vec<((usize, usize), BitVec, BitVec)>,
+}
+
+/// The result of rewinding one decision in the backtracking search. `Resumed`
+/// carries the position the search should re-collapse next: the rewound
+/// cell, now with one fewer alternative.
+enum BacktrackOutcome {
+    Resumed((usize, usize)),
+    Exhausted,
+}
+
+
 struct OverlappingModel {
     model: Array2<UncertainCell>,
     palette: Vec<RGB>,
     states: Vec<(Array2<RGB>, usize)>,
     state_size: usize,
+    periodic: bool,
 }
 
 impl OverlappingModel {
     pub fn from_seed_image(seed_image: SeedImage,
                            output_dims: (usize, usize),
-                           block_size: usize)
+                           block_size: usize,
+                           symmetry: usize,
+                           periodic: bool,
+                           max_colors: Option<usize>)
                            -> OverlappingModel {
-        let palette = OverlappingModel::build_color_palette(&seed_image.image_data);
-        let states = OverlappingModel::build_block_frequency_map(&seed_image.image_data,
-                                                                 block_size);
+        let image_data = match max_colors {
+            Some(max_colors) => OverlappingModel::quantize_image(&seed_image.image_data, max_colors),
+            None => seed_image.image_data.clone(),
+        };
+
+        let palette = OverlappingModel::build_color_palette(&image_data);
+        let states = OverlappingModel::build_block_frequency_map(&image_data,
+                                                                 block_size,
+                                                                 symmetry);
 
         let num_colors = palette.len();
         let num_states = states.len();
@@ -109,7 +139,338 @@ impl OverlappingModel {
             palette: palette,
             states: states,
             state_size: block_size,
+            periodic: periodic,
+        }
+    }
+
+    /// Runs the observe-propagate cycle to completion: repeatedly collapses the
+    /// lowest-entropy cell and propagates the resulting constraints until every
+    /// cell is decided or a contradiction is found.
+    pub fn run(&self) -> Result<(), ModelError> {
+        loop {
+            match self.step() {
+                Ok(()) => continue,
+                Err(ModelError::AllStatesDecided) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Performs a single observe-then-propagate step: finds the lowest nonzero
+    /// entropy cell, collapses it to one state, and propagates the resulting
+    /// constraint out to a fixpoint.
+    fn step(&self) -> Result<(), ModelError> {
+        let position = self.find_lowest_nonzero_entropy_coordinates()?;
+        self.model[position].collapse(&self.states);
+        let mut touched = HashSet::new();
+        let mut undo_log = Vec::new();
+        self.propagate(position, &mut touched, &mut undo_log)
+    }
+
+    /// Runs the solver with contradiction recovery. Whenever propagation hits a
+    /// dead end, rewinds the grid to the most recent collapse, forbids the
+    /// choice that led there, and retries — escalating to earlier decisions as
+    /// alternatives run out, up to `backtrack_limit` rewinds. If backtracking is
+    /// exhausted before the grid is fully decided, the grid is reset and the
+    /// whole run is retried from scratch, up to `max_restarts` times.
+    pub fn run_with_backtracking(&self,
+                                 backtrack_limit: usize,
+                                 max_restarts: usize)
+                                 -> Result<(), ModelError> {
+        let mut last_err = ModelError::BacktrackExhausted;
+
+        for attempt in 0..(max_restarts + 1) {
+            if attempt > 0 {
+                self.reset();
+            }
+
+            match self.solve_with_backtracking(backtrack_limit) {
+                Ok(()) => return Ok(()),
+                Err(ModelError::UnexpectedNaN(index)) => return Err(ModelError::UnexpectedNaN(index)),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Drives one full attempt of the observe-propagate cycle with contradiction
+    /// recovery, maintaining a stack of decisions to rewind through. A forced
+    /// collapse always re-runs `propagate` before the next cell is picked, so a
+    /// cell left with a single alternative after backtracking is never treated
+    /// as already decided without its neighbors being narrowed to match.
+    /// Returns `Err(ModelError::BacktrackExhausted)` if the backtracking budget
+    /// runs out without reaching a fully-decided grid, or surfaces
+    /// `ModelError::UnexpectedNaN` immediately, since no amount of backtracking
+    /// fixes a genuine entropy computation bug.
+    fn solve_with_backtracking(&self, backtrack_limit: usize) -> Result<(), ModelError> {
+        let mut decisions: Vec<Decision> = Vec::new();
+        let mut backtracks_remaining = backtrack_limit;
+
+        let mut next_position = match self.find_lowest_nonzero_entropy_coordinates() {
+            Ok(position) => Some(position),
+            Err(ModelError::AllStatesDecided) => return Ok(()),
+            Err(ModelError::UnexpectedNaN(index)) => return Err(ModelError::UnexpectedNaN(index)),
+            Err(_) => None,
+        };
+
+        loop {
+            let position = match next_position {
+                Some(position) => position,
+                None => {
+                    match self.find_lowest_nonzero_entropy_coordinates() {
+                        Ok(position) => position,
+                        Err(ModelError::AllStatesDecided) => return Ok(()),
+                        Err(ModelError::UnexpectedNaN(index)) => {
+                            return Err(ModelError::UnexpectedNaN(index))
+                        }
+                        Err(_) => {
+                            match self.backtrack(&mut decisions, &mut backtracks_remaining) {
+                                BacktrackOutcome::Resumed(p) => p,
+                                BacktrackOutcome::Exhausted => {
+                                    return Err(ModelError::BacktrackExhausted)
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+
+            let mut touched = HashSet::new();
+            let mut undo_log = Vec::new();
+            self.record_touch(position, &mut touched, &mut undo_log);
+            let chosen_state = self.model[position].collapse(&self.states);
+
+            match self.propagate(position, &mut touched, &mut undo_log) {
+                Ok(()) => {
+                    decisions.push(Decision {
+                        position: position,
+                        chosen_state: chosen_state,
+                        undo_log: undo_log,
+                    });
+                    next_position = None;
+                }
+                Err(_) => {
+                    self.restore(&undo_log);
+                    self.forbid_state(decisions.last_mut(), position, chosen_state);
+
+                    if self.model[position].possible_states.borrow().none() {
+                        match self.backtrack(&mut decisions, &mut backtracks_remaining) {
+                            BacktrackOutcome::Resumed(p) => next_position = Some(p),
+                            BacktrackOutcome::Exhausted => {
+                                return Err(ModelError::BacktrackExhausted)
+                            }
+                        }
+                    } else {
+                        // Retry the same cell with its remaining alternatives; it
+                        // must go through collapse + propagate again even if only
+                        // one alternative is left, so its neighbors get narrowed.
+                        next_position = Some(position);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rewinds the search by one decision: restores the grid to its state
+    /// before that decision's collapse and forbids the choice that caused a
+    /// contradiction. Keeps escalating to earlier decisions whenever the
+    /// rewound cell has no alternatives left, and reports `Exhausted` once
+    /// decisions or `backtracks_remaining` run out.
+    fn backtrack(&self,
+                decisions: &mut Vec<Decision>,
+                backtracks_remaining: &mut usize)
+                -> BacktrackOutcome {
+        loop {
+            if *backtracks_remaining == 0 {
+                return BacktrackOutcome::Exhausted;
+            }
+
+            let decision = match decisions.pop() {
+                Some(decision) => decision,
+                None => return BacktrackOutcome::Exhausted,
+            };
+            *backtracks_remaining -= 1;
+
+            self.restore(&decision.undo_log);
+            self.forbid_state(decisions.last_mut(), decision.position, decision.chosen_state);
+
+            if !self.model[decision.position].possible_states.borrow().none() {
+                return BacktrackOutcome::Resumed(decision.position);
+            }
+        }
+    }
+
+    /// Records `position`'s possible colors and possible states into
+    /// `undo_log`, the first time the current decision touches it. Scoping
+    /// the log to cells a decision's `collapse` and `propagate` actually
+    /// mutate — rather than deep-cloning the whole grid per decision — keeps
+    /// a decision's cost proportional to its propagation frontier instead of
+    /// the grid size.
+    fn record_touch(&self,
+                    position: (usize, usize),
+                    touched: &mut HashSet<(usize, usize)>,
+                    undo_log: &mut Vec<((usize, usize), BitVec, BitVec)>) {
+        if touched.insert(position) {
+            let cell = &self.model[position];
+            undo_log.push((position,
+                           cell.possible_colors.borrow().clone(),
+                           cell.possible_states.borrow().clone()));
+        }
+    }
+
+    /// Restores every cell recorded in `undo_log` to the possible colors and
+    /// possible states it had just before the decision that built the log.
+    fn restore(&self, undo_log: &[((usize, usize), BitVec, BitVec)]) {
+        for &(position, ref colors, ref states) in undo_log {
+            *self.model[position].possible_colors.borrow_mut() = colors.clone();
+            *self.model[position].possible_states.borrow_mut() = states.clone();
+        }
+    }
+
+    /// Marks a single state as forbidden at `position`. The cell is first
+    /// recorded into the undo log of `parent`, the decision now on top of the
+    /// stack, so that rewinding `parent` later also lifts this forbid.
+    fn forbid_state(&self,
+                    parent: Option<&mut Decision>,
+                    position: (usize, usize),
+                    state_index: usize) {
+        if let Some(parent) = parent {
+            if !parent.undo_log.iter().any(|&(p, _, _)| p == position) {
+                let cell = &self.model[position];
+                parent.undo_log.push((position,
+                                      cell.possible_colors.borrow().clone(),
+                                      cell.possible_states.borrow().clone()));
+            }
+        }
+        self.model[position].possible_states.borrow_mut().set(state_index, false);
+    }
+
+    /// Resets every cell back to fully unconstrained, for a full restart once
+    /// the backtracking budget is exhausted.
+    fn reset(&self) {
+        let num_colors = self.palette.len();
+        let num_states = self.states.len();
+
+        for cell in self.model.iter() {
+            *cell.possible_colors.borrow_mut() = BitVec::from_elem(num_colors, true);
+            *cell.possible_states.borrow_mut() = BitVec::from_elem(num_states, true);
+        }
+    }
+
+    /// Worklist-based constraint propagation, AC-3 style: starting from `origin`,
+    /// repeatedly narrows the possible colors of every cell a surviving state
+    /// overlaps, recomputes the possible states of whichever cells could be
+    /// affected by that narrowing, and pushes any cell whose states shrank back
+    /// onto the worklist. Runs until the worklist drains or a cell is left with
+    /// no possible colors or states.
+    fn propagate(&self,
+                origin: (usize, usize),
+                touched: &mut HashSet<(usize, usize)>,
+                undo_log: &mut Vec<((usize, usize), BitVec, BitVec)>)
+                -> Result<(), ModelError> {
+        let num_colors = self.palette.len();
+        let mut worklist = VecDeque::new();
+        worklist.push_back(origin);
+
+        while let Some(position) = worklist.pop_front() {
+            let surviving_states: Vec<usize> = {
+                let possible_states = self.model[position].possible_states.borrow();
+                possible_states.iter().enumerate().filter(|&(_, p)| p).map(|(i, _)| i).collect()
+            };
+
+            if surviving_states.is_empty() {
+                return Err(ModelError::NoValidStates(position));
+            }
+
+            for dy in 0..self.state_size {
+                for dx in 0..self.state_size {
+                    let offset_coord = match self.offset_coordinate(position, (dy as isize, dx as isize)) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+
+                    let mut allowed_colors = BitVec::from_elem(num_colors, false);
+                    for &state_index in &surviving_states {
+                        let &(ref state, _) = &self.states[state_index];
+                        let color = self.color_to_index(&state[(dy, dx)]);
+                        allowed_colors.set(color, true);
+                    }
+
+                    self.record_touch(offset_coord, touched, undo_log);
+
+                    let changed = self.model[offset_coord]
+                        .possible_colors
+                        .borrow_mut()
+                        .intersect(&allowed_colors);
+
+                    if !changed {
+                        continue;
+                    }
+
+                    if self.model[offset_coord].possible_colors.borrow().none() {
+                        return Err(ModelError::NoValidStates(offset_coord));
+                    }
+
+                    self.recompute_possible_states(offset_coord, &mut worklist, touched, undo_log)?;
+                }
+            }
         }
+
+        Ok(())
+    }
+
+    /// Re-derives `possible_states` for every cell whose states could reference
+    /// `changed_colors` (i.e. every cell within `state_size - 1` positions before
+    /// it in each direction), pushing any cell whose states shrank back onto
+    /// `worklist`.
+    fn recompute_possible_states(&self,
+                                 changed_colors: (usize, usize),
+                                 worklist: &mut VecDeque<(usize, usize)>,
+                                 touched: &mut HashSet<(usize, usize)>,
+                                 undo_log: &mut Vec<((usize, usize), BitVec, BitVec)>)
+                                 -> Result<(), ModelError> {
+        let radius = (self.state_size - 1) as isize;
+
+        for dy in -radius..1 {
+            for dx in -radius..1 {
+                let neighbor = match self.offset_coordinate(changed_colors, (dy, dx)) {
+                    Some(c) => c,
+                    None => continue,
+                };
+
+                self.record_touch(neighbor, touched, undo_log);
+
+                let valid_state_indices = self.valid_states_at_position(neighbor);
+
+                let (before, after) = {
+                    let mut possible_states = self.model[neighbor].possible_states.borrow_mut();
+                    let before = possible_states.iter().filter(|p| *p).count();
+
+                    let mut color_consistent = BitVec::from_elem(possible_states.len(), false);
+                    for &index in &valid_state_indices {
+                        color_consistent.set(index, true);
+                    }
+                    // Intersect rather than overwrite: `valid_states_at_position` only
+                    // checks colors, so overwriting would resurrect states already
+                    // ruled out elsewhere (e.g. by backtracking's `forbid_state`).
+                    possible_states.intersect(&color_consistent);
+
+                    let after = possible_states.iter().filter(|p| *p).count();
+                    (before, after)
+                };
+
+                if after == 0 {
+                    return Err(ModelError::NoValidStates(neighbor));
+                }
+
+                if after < before {
+                    worklist.push_back(neighbor);
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn find_lowest_nonzero_entropy_coordinates(&self) -> Result<(usize, usize), ModelError> {
@@ -146,6 +507,28 @@ impl OverlappingModel {
         (y < self_y) && (x < self_x)
     }
 
+    /// Offsets `coord` by `delta`, wrapping modulo the output dimensions when
+    /// `periodic` is set so that blocks near the border see the opposite edge
+    /// as a real neighbor instead of running off the grid. In non-periodic mode
+    /// this returns `None` for any coordinate that falls outside the grid.
+    fn offset_coordinate(&self, coord: (usize, usize), delta: (isize, isize)) -> Option<(usize, usize)> {
+        let (dim_y, dim_x) = self.model.dim();
+        let y = coord.0 as isize + delta.0;
+        let x = coord.1 as isize + delta.1;
+
+        if self.periodic {
+            let wrap = |v: isize, dim: usize| -> usize {
+                let dim = dim as isize;
+                (((v % dim) + dim) % dim) as usize
+            };
+            Some((wrap(y, dim_y), wrap(x, dim_x)))
+        } else if y < 0 || x < 0 || !self.valid_coordinate((y as usize, x as usize)) {
+            None
+        } else {
+            Some((y as usize, x as usize))
+        }
+    }
+
     fn valid_states_at_position(&self, position: (usize, usize)) -> Vec<usize> {
         let p = position;
         let mut valid_state_indices = Vec::<usize>::with_capacity(self.states.len());
@@ -153,8 +536,10 @@ impl OverlappingModel {
         'state: for (state_index, state) in self.states.iter().map(|&(ref s, _)| s).enumerate() {
             for (coord, color) in state.indexed_iter() {
                 let color = self.color_to_index(color);
-                let offset_coord = (p.0 + coord.0, p.1 + coord.1);
-                if !self.valid_coordinate(offset_coord) {continue 'state;}
+                let offset_coord = match self.offset_coordinate(p, (coord.0 as isize, coord.1 as isize)) {
+                    Some(c) => c,
+                    None => continue 'state,
+                };
                 if !self.model[offset_coord].valid_color(color) {continue 'state;}
             }
             valid_state_indices.push(state_index);
@@ -172,21 +557,193 @@ impl OverlappingModel {
         palette
     }
 
+    /// Reduces `image_data` to at most `max_colors` distinct colors by snapping
+    /// every pixel to the nearest entry of a median-cut palette, so that
+    /// `build_color_palette` subsequently produces a usably small state count
+    /// for photographic inputs.
+    fn quantize_image(image_data: &Array2<RGB>, max_colors: usize) -> Array2<RGB> {
+        let colors: Vec<RGB> = image_data.iter().cloned().collect();
+        let palette = OverlappingModel::median_cut_palette(&colors, max_colors);
+        image_data.map(|color| OverlappingModel::nearest_color(&palette, color))
+    }
+
+    /// Builds a palette of at most `max_colors` representative colors using
+    /// median-cut: recursively splits the widest color box (by largest channel
+    /// spread) at its median along that channel, until the target box count is
+    /// reached, then emits each box's average color. A `max_colors` of 0 leaves
+    /// the colors unreduced and returns every unique color.
+    fn median_cut_palette(colors: &[RGB], max_colors: usize) -> Vec<RGB> {
+        let mut unique: Vec<RGB> = colors.to_vec();
+        unique.sort();
+        unique.dedup();
+
+        if max_colors == 0 || unique.len() <= max_colors {
+            return unique;
+        }
+
+        let mut boxes = vec![unique];
+
+        while boxes.len() < max_colors {
+            let split_index = match OverlappingModel::widest_box(&boxes) {
+                Some(i) => i,
+                None => break,
+            };
+
+            let box_to_split = boxes.swap_remove(split_index);
+            let (left, right) = OverlappingModel::split_box(box_to_split);
+            boxes.push(left);
+            boxes.push(right);
+        }
+
+        boxes.iter().map(|b| OverlappingModel::average_color(b)).collect()
+    }
+
+    fn widest_box(boxes: &[Vec<RGB>]) -> Option<usize> {
+        boxes.iter()
+            .enumerate()
+            .filter(|&(_, b)| b.len() > 1)
+            .max_by_key(|&(_, b)| OverlappingModel::channel_spread(b))
+            .map(|(i, _)| i)
+    }
+
+    fn channel_spread(colors: &[RGB]) -> u32 {
+        let (r_range, g_range, b_range) = OverlappingModel::channel_ranges(colors);
+        r_range.max(g_range).max(b_range)
+    }
+
+    fn channel_ranges(colors: &[RGB]) -> (u32, u32, u32) {
+        let (mut r_min, mut g_min, mut b_min) = (u8::max_value(), u8::max_value(), u8::max_value());
+        let (mut r_max, mut g_max, mut b_max) = (u8::min_value(), u8::min_value(), u8::min_value());
+
+        for color in colors {
+            r_min = r_min.min(color.r);
+            r_max = r_max.max(color.r);
+            g_min = g_min.min(color.g);
+            g_max = g_max.max(color.g);
+            b_min = b_min.min(color.b);
+            b_max = b_max.max(color.b);
+        }
+
+        ((r_max - r_min) as u32, (g_max - g_min) as u32, (b_max - b_min) as u32)
+    }
+
+    /// Splits `colors` into two boxes along its widest channel, dividing at the
+    /// median so each half contains roughly equal population.
+    fn split_box(mut colors: Vec<RGB>) -> (Vec<RGB>, Vec<RGB>) {
+        let (r_range, g_range, b_range) = OverlappingModel::channel_ranges(&colors);
+
+        if r_range >= g_range && r_range >= b_range {
+            colors.sort_by_key(|c| c.r);
+        } else if g_range >= b_range {
+            colors.sort_by_key(|c| c.g);
+        } else {
+            colors.sort_by_key(|c| c.b);
+        }
+
+        let mid = colors.len() / 2;
+        let right = colors.split_off(mid);
+        (colors, right)
+    }
+
+    fn average_color(colors: &[RGB]) -> RGB {
+        let len = colors.len() as u32;
+        let (mut r_sum, mut g_sum, mut b_sum) = (0u32, 0u32, 0u32);
+
+        for color in colors {
+            r_sum += color.r as u32;
+            g_sum += color.g as u32;
+            b_sum += color.b as u32;
+        }
+
+        RGB {
+            r: (r_sum / len) as u8,
+            g: (g_sum / len) as u8,
+            b: (b_sum / len) as u8,
+        }
+    }
+
+    /// Finds the palette entry closest to `color` by squared Euclidean distance
+    /// in RGB space, so quantized pixels remain `color_to_index`-compatible.
+    fn nearest_color(palette: &[RGB], color: &RGB) -> RGB {
+        palette.iter()
+            .min_by_key(|candidate| OverlappingModel::color_distance(candidate, color))
+            .cloned()
+            .expect("Palette must not be empty!")
+    }
+
+    fn color_distance(a: &RGB, b: &RGB) -> u32 {
+        let dr = a.r as i32 - b.r as i32;
+        let dg = a.g as i32 - b.g as i32;
+        let db = a.b as i32 - b.b as i32;
+        (dr * dr + dg * dg + db * db) as u32
+    }
+
     fn build_block_frequency_map(image_data: &Array2<RGB>,
-                                 block_size: usize)
+                                 block_size: usize,
+                                 symmetry: usize)
                                  -> Vec<(Array2<RGB>, usize)> {
+        assert!(symmetry == 1 || symmetry == 2 || symmetry == 4 || symmetry == 8,
+               "symmetry level must be 1, 2, 4, or 8, got {}",
+               symmetry);
         let mut block_counts = HashMap::new();
 
-        //TODO augment with rotations and reflections
-
         for block in image_data.windows((block_size, block_size)) {
             let block = block.to_owned();
-            let count = block_counts.entry(block).or_insert(0);
-            *count += 1;
+            for variant in OverlappingModel::symmetry_variants(&block, symmetry) {
+                let count = block_counts.entry(variant).or_insert(0);
+                *count += 1;
+            }
         }
 
         block_counts.into_iter().collect()
     }
+
+    /// Rotates a square block 90 degrees clockwise.
+    fn rotate90(block: &Array2<RGB>) -> Array2<RGB> {
+        let (rows, cols) = block.dim();
+        Array2::from_shape_fn((cols, rows), |(r, c)| block[(rows - 1 - c, r)])
+    }
+
+    /// Mirrors a block left-to-right.
+    fn flip_horizontal(block: &Array2<RGB>) -> Array2<RGB> {
+        let (rows, cols) = block.dim();
+        Array2::from_shape_fn((rows, cols), |(r, c)| block[(r, cols - 1 - c)])
+    }
+
+    /// Generates the oriented variants of `block` for a given symmetry level,
+    /// each level being the full orientation set of a subgroup of D4
+    /// rather than an arbitrary prefix:
+    ///
+    /// - `1`: identity only, i.e. no symmetry augmentation.
+    /// - `2`: identity plus its 180-degree rotation.
+    /// - `4`: all four rotations (0, 90, 180, 270 degrees), no reflections.
+    /// - `8`: the full D4 group — all four rotations and their four
+    ///   reflections.
+    ///
+    /// `symmetry` must be one of those four values; callers go through
+    /// `build_block_frequency_map`, which asserts this. Identical variants of a
+    /// symmetric block are emitted more than once so they each contribute a
+    /// count, keeping frequency weighting proportional.
+    fn symmetry_variants(block: &Array2<RGB>, symmetry: usize) -> Vec<Array2<RGB>> {
+        let rotate90 = OverlappingModel::rotate90(block);
+        let rotate180 = OverlappingModel::rotate90(&rotate90);
+        let rotate270 = OverlappingModel::rotate90(&rotate180);
+        let flip = OverlappingModel::flip_horizontal(block);
+        let flip_rotate90 = OverlappingModel::rotate90(&flip);
+        let flip_rotate180 = OverlappingModel::rotate90(&flip_rotate90);
+        let flip_rotate270 = OverlappingModel::rotate90(&flip_rotate180);
+
+        let variants = vec![block.clone(),
+                            rotate180,
+                            rotate90,
+                            rotate270,
+                            flip,
+                            flip_rotate180,
+                            flip_rotate90,
+                            flip_rotate270];
+
+        variants.into_iter().take(symmetry).collect()
+    }
 }
 
 
@@ -194,4 +751,215 @@ enum ModelError {
     NoValidStates((usize, usize)),
     UnexpectedNaN((usize, usize)),
     AllStatesDecided,
+    BacktrackExhausted,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> RGB {
+        RGB { r: r, g: g, b: b }
+    }
+
+    fn build_model(states: Vec<(Array2<RGB>, usize)>,
+                   palette: Vec<RGB>,
+                   dims: (usize, usize),
+                   state_size: usize,
+                   periodic: bool)
+                   -> OverlappingModel {
+        let (y, x) = dims;
+        let num_colors = palette.len();
+        let num_states = states.len();
+        let mut cells = Vec::with_capacity(y * x);
+
+        for _ in 0..(y * x) {
+            cells.push(UncertainCell::new(num_colors, num_states));
+        }
+
+        OverlappingModel {
+            model: Array::from_shape_vec((y, x), cells).unwrap(),
+            palette: palette,
+            states: states,
+            state_size: state_size,
+            periodic: periodic,
+        }
+    }
+
+    #[test]
+    fn propagate_narrows_an_overlapping_neighbors_colors_and_states() {
+        let black = rgb(0, 0, 0);
+        let white = rgb(255, 255, 255);
+
+        let image_data = Array::from_shape_vec((1, 2), vec![black, white]).unwrap();
+        let palette = OverlappingModel::build_color_palette(&image_data);
+
+        // Two 2x2 checkerboard states that are each other's color-inverse.
+        let state_a = Array::from_shape_vec((2, 2), vec![black, white, white, black]).unwrap();
+        let state_b = Array::from_shape_vec((2, 2), vec![white, black, black, white]).unwrap();
+
+        let model = build_model(vec![(state_a, 1), (state_b, 1)], palette.clone(), (2, 2), 2, true);
+
+        // Force the origin cell into state_a, as if `collapse` had chosen it.
+        let origin = (0, 0);
+        model.model[origin].possible_states.borrow_mut().set(1, false);
+
+        let mut touched = HashSet::new();
+        let mut undo_log = Vec::new();
+        assert!(model.propagate(origin, &mut touched, &mut undo_log).is_ok(),
+               "a single surviving state must not contradict itself");
+
+        let black_index = model.color_to_index(&black);
+        let white_index = model.color_to_index(&white);
+
+        // The diagonally-opposite corner is only ever covered, in this 2x2
+        // periodic grid, by state_a's own (1, 1) entry, which is black.
+        let corner_colors = model.model[(1, 1)].possible_colors.borrow();
+        assert!(corner_colors.get(black_index).unwrap());
+        assert!(!corner_colors.get(white_index).unwrap());
+
+        // state_b is the color-inverse of state_a, so once the grid is pinned
+        // to state_a's checkerboard, state_b can no longer be anchored at a
+        // cell of the origin's parity such as (1, 1).
+        let corner_states = model.model[(1, 1)].possible_states.borrow();
+        assert!(corner_states.get(0).unwrap());
+        assert!(!corner_states.get(1).unwrap());
+    }
+
+    #[test]
+    fn offset_coordinate_wraps_when_periodic() {
+        let model = build_model(vec![(Array::from_elem((1, 1), rgb(0, 0, 0)), 1)],
+                                vec![rgb(0, 0, 0)],
+                                (3, 3),
+                                1,
+                                true);
+
+        assert_eq!(model.offset_coordinate((0, 0), (-1, -1)), Some((2, 2)));
+        assert_eq!(model.offset_coordinate((2, 2), (1, 1)), Some((0, 0)));
+    }
+
+    #[test]
+    fn offset_coordinate_rejects_out_of_range_when_not_periodic() {
+        let model = build_model(vec![(Array::from_elem((1, 1), rgb(0, 0, 0)), 1)],
+                                vec![rgb(0, 0, 0)],
+                                (3, 3),
+                                1,
+                                false);
+
+        assert_eq!(model.offset_coordinate((0, 0), (-1, 0)), None);
+        assert_eq!(model.offset_coordinate((2, 2), (1, 0)), None);
+    }
+
+    #[test]
+    fn backtrack_rewinds_and_forbids_the_choice_that_failed() {
+        let model = build_model(vec![(Array::from_elem((1, 1), rgb(0, 0, 0)), 1),
+                                     (Array::from_elem((1, 1), rgb(255, 255, 255)), 1)],
+                                vec![rgb(0, 0, 0), rgb(255, 255, 255)],
+                                (1, 1),
+                                1,
+                                false);
+        let position = (0, 0);
+
+        let mut touched = HashSet::new();
+        let mut undo_log = Vec::new();
+        model.record_touch(position, &mut touched, &mut undo_log);
+        // Simulate having collapsed to state 0 by forbidding state 1 here.
+        model.model[position].possible_states.borrow_mut().set(1, false);
+
+        let mut decisions = vec![Decision {
+                                     position: position,
+                                     chosen_state: 0,
+                                     undo_log: undo_log,
+                                 }];
+        let mut backtracks_remaining = 4;
+
+        match model.backtrack(&mut decisions, &mut backtracks_remaining) {
+            BacktrackOutcome::Resumed(resumed_at) => assert_eq!(resumed_at, position),
+            BacktrackOutcome::Exhausted => panic!("the untried state should let the search resume"),
+        }
+
+        let possible_states = model.model[position].possible_states.borrow();
+        assert!(!possible_states.get(0).unwrap(), "the failed choice must stay forbidden");
+        assert!(possible_states.get(1).unwrap(), "the untried state must still be available");
+    }
+
+    #[test]
+    fn run_collapses_every_cell_to_all_states_decided() {
+        let black = rgb(0, 0, 0);
+        let white = rgb(255, 255, 255);
+
+        let model = build_model(vec![(Array::from_elem((1, 1), black), 1),
+                                     (Array::from_elem((1, 1), white), 1)],
+                                vec![black, white],
+                                (1, 1),
+                                1,
+                                false);
+
+        assert!(model.run().is_ok(), "a single undecided cell must collapse cleanly");
+
+        let possible_states = model.model[(0, 0)].possible_states.borrow();
+        assert_eq!(possible_states.iter().filter(|p| *p).count(),
+                  1,
+                  "run() must leave every cell decided to exactly one state");
+    }
+
+    #[test]
+    fn run_with_backtracking_reports_exhaustion_once_no_decision_can_recover() {
+        let model = build_model(vec![(Array::from_elem((1, 1), rgb(0, 0, 0)), 1)],
+                                vec![rgb(0, 0, 0)],
+                                (1, 1),
+                                1,
+                                false);
+
+        // No decision was ever made, so there is nothing to rewind to.
+        model.model[(0, 0)].possible_states.borrow_mut().clear();
+
+        let result = model.run_with_backtracking(4, 0);
+        let exhausted = match result {
+            Err(ModelError::BacktrackExhausted) => true,
+            _ => false,
+        };
+        assert!(exhausted, "an unrecoverable grid with no restarts left must report exhaustion");
+    }
+
+    #[test]
+    fn symmetry_variants_selects_the_documented_subgroup_for_each_level() {
+        let top_left = rgb(1, 0, 0);
+        let top_right = rgb(2, 0, 0);
+        let bottom_left = rgb(3, 0, 0);
+        let bottom_right = rgb(4, 0, 0);
+        let block = Array::from_shape_vec((2, 2),
+                                          vec![top_left, top_right, bottom_left, bottom_right])
+            .unwrap();
+
+        assert_eq!(OverlappingModel::symmetry_variants(&block, 1).len(), 1);
+        assert_eq!(OverlappingModel::symmetry_variants(&block, 2).len(), 2);
+        assert_eq!(OverlappingModel::symmetry_variants(&block, 4).len(), 4);
+        assert_eq!(OverlappingModel::symmetry_variants(&block, 8).len(), 8);
+
+        // Level 4 is the pure rotation group: no reflection (the mirrored top
+        // row) should appear among its variants.
+        let mirrored = Array::from_shape_vec((2, 2),
+                                             vec![top_right, top_left, bottom_right, bottom_left])
+            .unwrap();
+        assert!(!OverlappingModel::symmetry_variants(&block, 4).contains(&mirrored));
+        assert!(OverlappingModel::symmetry_variants(&block, 8).contains(&mirrored));
+    }
+
+    #[test]
+    fn median_cut_palette_reduces_to_the_requested_size() {
+        let colors: Vec<RGB> = (0..50u8).map(|i| rgb(i, i, i)).collect();
+        let palette = OverlappingModel::median_cut_palette(&colors, 4);
+        assert!(palette.len() <= 4);
+    }
+
+    #[test]
+    fn nearest_color_snaps_to_a_palette_entry() {
+        let colors: Vec<RGB> = (0..50u8).map(|i| rgb(i, i, i)).collect();
+        let palette = OverlappingModel::median_cut_palette(&colors, 4);
+
+        let nearest = OverlappingModel::nearest_color(&palette, &rgb(10, 10, 10));
+        assert!(palette.contains(&nearest));
+    }
 }